@@ -1,9 +1,16 @@
+use aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key as AesKey, Nonce as AesNonce};
+use argon2::Argon2;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::{ChaCha20Poly1305, Key as ChaChaKey, Nonce as ChaChaNonce};
 use crc::crc32;
+use rand::{rngs::OsRng, RngCore};
 use std::{
     collections::HashMap,
+    error::Error,
+    fmt,
     fs::{self, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
     path::Path,
     sync::{Arc, Mutex},
 };
@@ -11,20 +18,424 @@ use std::{
 type ByteStr = [u8];
 type ByteString = Vec<u8>;
 
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// Magic signature modelled on PNG's: a non-ASCII first byte so the file is
+// never mistaken for text, the crate name, and a CR-LF-EOF-LF tail so
+// transfers that mangle line endings are caught immediately.
+const MAGIC: [u8; 8] = [0x81, b'k', b'v', b's', b'\r', b'\n', 0x1A, b'\n'];
+const FORMAT_VERSION: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+// Set once a file has been through `compact`: it's a sorted, block-structured
+// segment with a trailing sparse index rather than a plain append-only log.
+const FLAG_SEGMENT: u8 = 0b0000_0010;
+// Selected at open; only actually used once `compact` writes blocks.
+const FLAG_COMPRESSED: u8 = 0b0000_0100;
+
+// Guards against treating a header read from garbage/corrupt bytes as a
+// huge, trustworthy record length and trying to allocate for it.
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+// Target size of a block's entries before `compact` starts a new one.
+// Approximate: a block is flushed before the entry that would cross this
+// threshold is added, so a block only exceeds it when its first entry does.
+const BLOCK_TARGET_LEN: usize = 4096;
+// How many entries separate each full (non-prefix-compressed) restart key
+// within a block.
+const BLOCK_RESTART_INTERVAL: usize = 16;
+
+/// Errors raised while validating a log file's header.
+#[derive(Debug)]
+pub enum HeaderError {
+    /// The first 8 bytes don't match the `kvs` magic signature.
+    BadMagic,
+    /// The header's format version isn't one this build knows how to read.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::BadMagic => write!(f, "not a kvs log file (bad magic signature)"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "unsupported log format version {}", version)
+            }
+        }
+    }
+}
+
+impl Error for HeaderError {}
+
+/// Errors raised while decoding a single record, in place of the old
+/// behaviour of panicking on a CRC mismatch.
+#[derive(Debug)]
+pub enum RecordError {
+    Io(io::Error),
+    /// The record's stored checksum doesn't match the checksum of the data
+    /// actually read; the body itself is corrupt.
+    ChecksumMismatch { offset: u64, expected: u32, actual: u32 },
+    /// `key_len`/`val_len` are too large to plausibly be real, meaning the
+    /// length header itself was read from corrupt bytes.
+    ImpossibleLength { offset: u64, key_len: u32, val_len: u32 },
+    /// The record-type tag isn't one we know about, meaning either the
+    /// header was read from corrupt bytes or the file is from a newer
+    /// format version.
+    InvalidRecordType { offset: u64, tag: u8 },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Io(err) => write!(f, "{}", err),
+            RecordError::ChecksumMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "checksum mismatch at offset {} ({:08x} != {:08x})",
+                offset, expected, actual
+            ),
+            RecordError::ImpossibleLength {
+                offset,
+                key_len,
+                val_len,
+            } => write!(
+                f,
+                "implausible record lengths at offset {} (key_len={}, val_len={})",
+                offset, key_len, val_len
+            ),
+            RecordError::InvalidRecordType { offset, tag } => write!(
+                f,
+                "unrecognized record type tag {} at offset {}",
+                tag, offset
+            ),
+        }
+    }
+}
+
+impl Error for RecordError {}
+
+impl From<io::Error> for RecordError {
+    fn from(err: io::Error) -> Self {
+        RecordError::Io(err)
+    }
+}
+
+impl From<RecordError> for io::Error {
+    fn from(err: RecordError) -> Self {
+        match err {
+            RecordError::Io(err) => err,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// Summary produced by [`KvStore::scan`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanStats {
+    pub valid: u64,
+    pub corrupt: u64,
+    pub truncated: u64,
+}
+
+/// Which AEAD cipher is used to encrypt records at rest.
+///
+/// Persisted as a single byte alongside the key-derivation salt so an
+/// existing file can be re-opened without the caller having to remember
+/// which cipher it was written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionType {
+    AesGcm,
+    Chacha20Poly1305,
+}
+
+impl EncryptionType {
+    fn tag(self) -> u8 {
+        match self {
+            EncryptionType::AesGcm => 0,
+            EncryptionType::Chacha20Poly1305 => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(EncryptionType::AesGcm),
+            1 => Ok(EncryptionType::Chacha20Poly1305),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown encryption type tag {}", other),
+            )),
+        }
+    }
+}
+
+// Key material derived from the store's passphrase, plus the salt it was
+// derived with so `compact` can re-emit the same header on a fresh file.
+#[derive(Clone)]
+struct Encryption {
+    kind: EncryptionType,
+    key: [u8; 32],
+    salt: [u8; SALT_LEN],
+}
+
+impl Encryption {
+    fn encrypt(&self, plaintext: &[u8]) -> io::Result<(ByteString, [u8; NONCE_LEN])> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let ciphertext = match self.kind {
+            EncryptionType::AesGcm => {
+                let cipher = Aes256Gcm::new(AesKey::<Aes256Gcm>::from_slice(&self.key));
+                cipher
+                    .encrypt(AesNonce::from_slice(&nonce_bytes), plaintext)
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?
+            }
+            EncryptionType::Chacha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new(ChaChaKey::from_slice(&self.key));
+                cipher
+                    .encrypt(ChaChaNonce::from_slice(&nonce_bytes), plaintext)
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?
+            }
+        };
+
+        Ok((ciphertext, nonce_bytes))
+    }
+
+    fn decrypt(&self, nonce_bytes: &[u8], ciphertext: &[u8]) -> io::Result<ByteString> {
+        let plaintext = match self.kind {
+            EncryptionType::AesGcm => {
+                let cipher = Aes256Gcm::new(AesKey::<Aes256Gcm>::from_slice(&self.key));
+                cipher.decrypt(AesNonce::from_slice(nonce_bytes), ciphertext)
+            }
+            EncryptionType::Chacha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new(ChaChaKey::from_slice(&self.key));
+                cipher.decrypt(ChaChaNonce::from_slice(nonce_bytes), ciphertext)
+            }
+        }
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decryption failed (wrong passphrase or corrupt data)",
+            )
+        })?;
+
+        Ok(plaintext)
+    }
+}
+
+// Builds the fixed magic/version/flags header, followed by the encryption
+// type tag and salt when the store is encrypted.
+fn build_header(encryption: Option<&Encryption>, is_segment: bool, compressed: bool) -> ByteString {
+    let mut header = Vec::with_capacity(MAGIC.len() + 2 + 1 + SALT_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.push(FORMAT_VERSION);
+
+    let mut flags = 0;
+    if encryption.is_some() {
+        flags |= FLAG_ENCRYPTED;
+    }
+    if is_segment {
+        flags |= FLAG_SEGMENT;
+    }
+    if compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+    header.push(flags);
+
+    if let Some(encryption) = encryption {
+        header.push(encryption.kind.tag());
+        header.extend_from_slice(&encryption.salt);
+    }
+
+    header
+}
+
+// Builds the on-disk bytes for one record: checksum, lengths, then the
+// (optionally encrypted) body, with the record type tag prepended to the
+// body so it's covered by the checksum/AEAD rather than left as bare
+// metadata. Shared by `write_record` (appending to the live log) and
+// `write_plain_log` (scan's fix mode), so both paths stay byte-for-byte
+// compatible with what `process_record` reads back.
+fn encode_record(
+    key: &ByteStr,
+    value: &ByteStr,
+    record_type: RecordType,
+    encryption: Option<&Encryption>,
+) -> io::Result<ByteString> {
+    let key_len = key.len();
+    let val_len = value.len();
+
+    let mut tmp = ByteString::with_capacity(1 + key_len + val_len);
+    tmp.push(record_type as u8);
+    tmp.extend_from_slice(key);
+    tmp.extend_from_slice(value);
+
+    let mut record = ByteString::new();
+    match encryption {
+        None => {
+            let checksum = crc32::checksum_ieee(&tmp);
+
+            record.write_u32::<LittleEndian>(checksum)?;
+            record.write_u32::<LittleEndian>(key_len as u32)?;
+            record.write_u32::<LittleEndian>(val_len as u32)?;
+            record.write_all(&tmp)?;
+        }
+        Some(encryption) => {
+            let (ciphertext, nonce) = encryption.encrypt(&tmp)?;
+            let checksum = crc32::checksum_ieee(&ciphertext);
+
+            record.write_u32::<LittleEndian>(checksum)?;
+            record.write_u32::<LittleEndian>(key_len as u32)?;
+            record.write_u32::<LittleEndian>(val_len as u32)?;
+            record.write_all(&nonce)?;
+            record.write_all(&ciphertext)?;
+        }
+    }
+
+    Ok(record)
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8; SALT_LEN]) -> io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("key derivation failed: {}", err),
+            )
+        })?;
+    Ok(key)
+}
+
+// Distinguishes a live write from a delete marker so an empty value can be
+// stored legitimately and `compact` knows which keys to drop for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordType {
+    Value = 0,
+    Tombstone = 1,
+}
+
+impl RecordType {
+    fn from_tag(offset: u64, tag: u8) -> Result<Self, RecordError> {
+        match tag {
+            0 => Ok(RecordType::Value),
+            1 => Ok(RecordType::Tombstone),
+            tag => Err(RecordError::InvalidRecordType { offset, tag }),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
+    is_tombstone: bool,
+}
+
+// The first key of a block and the file offset its block starts at.
+#[derive(Debug, Clone)]
+struct SparseIndexEntry {
+    first_key: ByteString,
+    offset: u64,
+}
+
+// `Full` is a plain append-only log: every live key's exact offset is known.
+// `Sparse` is what `compact` produces: a sorted, block-structured segment
+// where only each block's first key is kept in memory, and a lookup has to
+// decode the candidate block to find the value.
+#[derive(Debug)]
+enum Index {
+    Full(HashMap<ByteString, u64>),
+    Sparse(Vec<SparseIndexEntry>),
+}
+
+// Reconstructs every (key, value) pair out of one decompressed block body
+// (entries followed by the restart footer), following the shared-prefix
+// encoding `compact` wrote it with.
+fn decode_block(block: &[u8]) -> io::Result<Vec<(ByteString, ByteString)>> {
+    let restart_count = (&block[block.len() - 4..]).read_u32::<LittleEndian>()? as usize;
+    let restarts_start = block.len() - 4 - restart_count * 4;
+    let mut cursor = Cursor::new(&block[..restarts_start]);
+
+    let mut entries = Vec::new();
+    let mut prev_key: ByteString = Vec::new();
+
+    while (cursor.position() as usize) < restarts_start {
+        let shared_len = cursor.read_u32::<LittleEndian>()? as usize;
+        let suffix_len = cursor.read_u32::<LittleEndian>()? as usize;
+        let mut suffix = vec![0u8; suffix_len];
+        cursor.read_exact(&mut suffix)?;
+        let val_len = cursor.read_u32::<LittleEndian>()? as usize;
+        let mut value = vec![0u8; val_len];
+        cursor.read_exact(&mut value)?;
+
+        let mut key = prev_key[..shared_len].to_vec();
+        key.extend_from_slice(&suffix);
+
+        prev_key = key.clone();
+        entries.push((key, value));
+    }
+
+    Ok(entries)
 }
 
 #[derive(Debug)]
 pub struct KvStore {
     f: Arc<Mutex<File>>,
-    pub index: Arc<Mutex<HashMap<ByteString, u64>>>,
+    index: Arc<Mutex<Index>>,
+    // Encryption is opt-in: `None` means records are stored as plaintext,
+    // exactly as before.
+    encryption: Option<Encryption>,
+    // Whether `compact` should Snappy-compress the blocks it writes.
+    compressed: bool,
+    // Byte offset of the first record, past the file header.
+    header_len: u64,
 }
 
 impl KvStore {
     pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, None, false)
+    }
+
+    /// Opens the store at `path` with per-record encryption, deriving the
+    /// key from `passphrase` via Argon2.
+    ///
+    /// On a fresh file a random salt is generated and written into the
+    /// header so a later `open_encrypted` call with the same passphrase
+    /// re-derives the same key; on an existing file the salt and cipher are
+    /// read back from that header.
+    pub fn open_encrypted(path: &Path, passphrase: &[u8], kind: EncryptionType) -> io::Result<Self> {
+        Self::open_with(path, Some((passphrase, kind)), false)
+    }
+
+    /// Opens the store at `path` with Snappy block compression enabled for
+    /// anything `compact` writes. The choice is persisted in the header's
+    /// flags byte, so a later plain `open` of the same file still compacts
+    /// with compression on.
+    pub fn open_compressed(path: &Path) -> io::Result<Self> {
+        Self::open_with(path, None, true)
+    }
+
+    /// Opens the store with both per-record encryption and, once compacted,
+    /// Snappy-compressed blocks.
+    pub fn open_encrypted_compressed(
+        path: &Path,
+        passphrase: &[u8],
+        kind: EncryptionType,
+    ) -> io::Result<Self> {
+        Self::open_with(path, Some((passphrase, kind)), true)
+    }
+
+    fn open_with(
+        path: &Path,
+        passphrase: Option<(&[u8], EncryptionType)>,
+        compressed: bool,
+    ) -> io::Result<Self> {
+        let is_new = fs::metadata(path).map(|m| m.len() == 0).unwrap_or(true);
+
         let f = OpenOptions::new()
             .read(true)
             .write(true)
@@ -32,41 +443,239 @@ impl KvStore {
             .append(true)
             .open(path)?;
 
-        let index = HashMap::new();
+        let (header_len, encryption, compressed, is_segment) = if is_new {
+            let encryption = match passphrase {
+                Some((passphrase, kind)) => {
+                    let mut salt = [0u8; SALT_LEN];
+                    OsRng.fill_bytes(&mut salt);
+                    let key = derive_key(passphrase, &salt)?;
+                    Some(Encryption { kind, key, salt })
+                }
+                None => None,
+            };
+
+            let header = build_header(encryption.as_ref(), false, compressed);
+            (&f).write_all(&header)?;
+
+            (header.len() as u64, encryption, compressed, false)
+        } else {
+            let mut header_file = File::open(path)?;
+
+            let mut magic = [0u8; MAGIC.len()];
+            header_file.read_exact(&mut magic)?;
+            if magic != MAGIC {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, HeaderError::BadMagic));
+            }
+
+            let version = header_file.read_u8()?;
+            if version != FORMAT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    HeaderError::UnsupportedVersion(version),
+                ));
+            }
+
+            let flags = header_file.read_u8()?;
+            let mut header_len = MAGIC.len() as u64 + 2;
+
+            let encryption = if flags & FLAG_ENCRYPTED != 0 {
+                let kind = EncryptionType::from_tag(header_file.read_u8()?)?;
+                let mut salt = [0u8; SALT_LEN];
+                header_file.read_exact(&mut salt)?;
+                header_len += 1 + SALT_LEN as u64;
+
+                let passphrase = passphrase.map(|(passphrase, _)| passphrase).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "store is encrypted; open it with open_encrypted and a passphrase",
+                    )
+                })?;
+                let key = derive_key(passphrase, &salt)?;
+                Some(Encryption { kind, key, salt })
+            } else {
+                if passphrase.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "store is not encrypted; open it with open() or open_compressed() instead of discarding the passphrase",
+                    ));
+                }
+                None
+            };
+
+            (
+                header_len,
+                encryption,
+                flags & FLAG_COMPRESSED != 0,
+                flags & FLAG_SEGMENT != 0,
+            )
+        };
+
+        // `load` fills this in: a `Full` map by scanning the log, or a
+        // `Sparse` index by reading the segment's trailing footer.
+        let index = if is_segment {
+            Index::Sparse(Vec::new())
+        } else {
+            Index::Full(HashMap::new())
+        };
 
         Ok(Self {
             f: Arc::new(Mutex::new(f)),
             index: Arc::new(Mutex::new(index)),
+            encryption,
+            compressed,
+            header_len,
         })
     }
 
     // Build in-memory index of the key-value pairs stored in file
     pub fn load(&mut self) -> io::Result<()> {
+        let is_segment = matches!(&*self.index.lock().unwrap(), Index::Sparse(_));
+
+        if is_segment {
+            self.load_segment_index()
+        } else {
+            self.load_log()
+        }
+    }
+
+    fn load_log(&mut self) -> io::Result<()> {
         let file_lock = self.f.lock().unwrap();
         let mut f = BufReader::new(&*file_lock);
+        f.seek(SeekFrom::Start(self.header_len))?;
 
         loop {
             let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = KvStore::process_record(&mut f);
+            let maybe_kv = KvStore::process_record(&mut f, self.encryption.as_ref(), position);
 
             let kv = match maybe_kv {
                 Ok(kv) => kv,
-                Err(err) => match err.kind() {
-                    io::ErrorKind::UnexpectedEof => {
-                        break;
-                    }
-                    _ => return Err(err),
-                },
+                Err(RecordError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(err) => return Err(err.into()),
             };
 
-            self.index.lock().unwrap().insert(kv.key, position);
+            match &mut *self.index.lock().unwrap() {
+                Index::Full(map) => {
+                    if kv.is_tombstone {
+                        map.remove(&kv.key);
+                    } else {
+                        map.insert(kv.key, position);
+                    }
+                }
+                Index::Sparse(_) => unreachable!("load_log only runs against log-format files"),
+            }
         }
 
         Ok(())
     }
 
-    // Layout:
-    // Fixed-width header
+    // Reads the trailing sparse index a `compact`ed segment ends with: a
+    // run of `[key_len][key][block offset]` entries, located via the u64
+    // pointer in the file's last 8 bytes.
+    fn load_segment_index(&mut self) -> io::Result<()> {
+        let file_lock = self.f.lock().unwrap();
+        let file_len = file_lock.metadata()?.len();
+        let mut f = BufReader::new(&*file_lock);
+
+        f.seek(SeekFrom::End(-8))?;
+        let index_offset = f.read_u64::<LittleEndian>()?;
+
+        f.seek(SeekFrom::Start(index_offset))?;
+        let mut remaining = file_len - 8 - index_offset;
+        let mut entries = Vec::new();
+
+        while remaining > 0 {
+            let key_len = f.read_u32::<LittleEndian>()? as u64;
+            let mut first_key = vec![0u8; key_len as usize];
+            f.read_exact(&mut first_key)?;
+            let offset = f.read_u64::<LittleEndian>()?;
+
+            remaining -= 4 + key_len + 8;
+            entries.push(SparseIndexEntry { first_key, offset });
+        }
+
+        *self.index.lock().unwrap() = Index::Sparse(entries);
+        Ok(())
+    }
+
+    /// Walks the log like `load`, but tolerates corruption instead of
+    /// panicking on it.
+    ///
+    /// In read-only mode (`fix = false`) this tallies how many records were
+    /// valid, corrupt (bad checksum or implausible lengths), or truncated
+    /// (a torn trailing record), without touching the file. In `fix` mode
+    /// it additionally rewrites the log so it contains only the last valid
+    /// version of each recoverable key, dropping the torn tail and any
+    /// corrupt records. This is a repair, not a compaction: the rewritten
+    /// file stays a plain, writable log, not a `compact`ed segment — call
+    /// `compact` yourself afterwards if you also want that.
+    pub fn scan(&mut self, fix: bool) -> io::Result<ScanStats> {
+        if matches!(&*self.index.lock().unwrap(), Index::Sparse(_)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scan only applies to a log-format store, not a compacted segment",
+            ));
+        }
+
+        let mut stats = ScanStats::default();
+        let mut recovered: HashMap<ByteString, u64> = HashMap::new();
+
+        {
+            let file_lock = self.f.lock().unwrap();
+            let file_len = file_lock.metadata()?.len();
+            let mut f = BufReader::new(&*file_lock);
+            f.seek(SeekFrom::Start(self.header_len))?;
+
+            loop {
+                let offset = f.seek(SeekFrom::Current(0))?;
+                if offset >= file_len {
+                    break;
+                }
+
+                match KvStore::process_record(&mut f, self.encryption.as_ref(), offset) {
+                    Ok(kv) => {
+                        stats.valid += 1;
+                        if kv.is_tombstone {
+                            recovered.remove(&kv.key);
+                        } else {
+                            recovered.insert(kv.key, offset);
+                        }
+                    }
+                    Err(RecordError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        // A torn trailing record; nothing reliable follows it.
+                        stats.truncated += 1;
+                        break;
+                    }
+                    Err(RecordError::Io(err)) => return Err(err),
+                    Err(RecordError::ChecksumMismatch { .. }) => {
+                        // The length header was readable, so the reader
+                        // already consumed the whole (corrupt) record;
+                        // resume scanning right after it.
+                        stats.corrupt += 1;
+                    }
+                    Err(RecordError::ImpossibleLength { .. })
+                    | Err(RecordError::InvalidRecordType { .. }) => {
+                        // The lengths (or the tag right after them) can't be
+                        // trusted, so we don't know where the next record
+                        // starts; stop rather than guess.
+                        stats.corrupt += 1;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if fix {
+            self.write_plain_log(recovered.into_values().collect())?;
+        }
+
+        Ok(stats)
+    }
+
+    // Layout (plaintext), checksum covers the record type tag along with
+    // the key and value so a flipped tag bit is caught as corruption
+    // instead of silently changing a value into a tombstone (or back):
     // +------------------------+
     // | Checksum (32 bytes)    |
     // +------------------------+
@@ -74,15 +683,33 @@ impl KvStore {
     // +------------------------+
     // | Value Length (32 bytes)|
     // +------------------------+
-    //
-    // Variable-length body:
+    // | Record Type (1 byte)   |
     // +------------------------+
     // | Key ([u8; key_len])    |
     // +------------------------+
     // | Value ([u8; value_len])|
     // +------------------------+
+    //
+    // Layout (encrypted); the record type tag is part of the plaintext the
+    // AEAD seals, so the checksum (over the ciphertext) and the AEAD tag
+    // both guard against it being tampered with or corrupted:
+    // +----------------------------+
+    // | Checksum (4 bytes)         |
+    // +----------------------------+
+    // | Key Length (4 bytes)       |
+    // +----------------------------+
+    // | Value Length (4 bytes)     |
+    // +----------------------------+
+    // | Nonce (12 bytes)           |
+    // +----------------------------+
+    // | Ciphertext (tag+key+val)   |
+    // +----------------------------+
 
-    fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
+    fn process_record<R: Read>(
+        f: &mut R,
+        encryption: Option<&Encryption>,
+        offset: u64,
+    ) -> Result<KeyValuePair, RecordError> {
         // we need to store data in a deterministic way -> diff platform - diff endianness
         // byteorder crate here guarantees how our byte sequences are interpreted
 
@@ -90,39 +717,294 @@ impl KvStore {
         let key_len = f.read_u32::<LittleEndian>()?;
         let val_len = f.read_u32::<LittleEndian>()?;
 
-        let data_len = key_len + val_len;
-        let mut data = ByteString::with_capacity(data_len as usize);
+        if key_len > MAX_RECORD_LEN || val_len > MAX_RECORD_LEN {
+            return Err(RecordError::ImpossibleLength {
+                offset,
+                key_len,
+                val_len,
+            });
+        }
+
+        let mut data = match encryption {
+            None => {
+                // +1 for the record type tag prepended to key+value.
+                let data_len = 1 + key_len + val_len;
+                let mut data = ByteString::with_capacity(data_len as usize);
+                f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+                // `read_to_end` on a `Take` doesn't error on a short read, so
+                // a torn trailing record (length header intact, body cut
+                // short by a crash) has to be caught here rather than by the
+                // reader, or it would silently pass corrupt data downstream.
+                if data.len() != data_len as usize {
+                    return Err(RecordError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated record body",
+                    )));
+                }
+
+                let checksum = crc32::checksum_ieee(&data);
+                if checksum != saved_checksum {
+                    return Err(RecordError::ChecksumMismatch {
+                        offset,
+                        expected: saved_checksum,
+                        actual: checksum,
+                    });
+                }
+
+                data
+            }
+            Some(encryption) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                f.read_exact(&mut nonce)?;
+
+                // AEAD tags add 16 bytes on top of the plaintext length,
+                // which itself has the record type tag prepended.
+                let ciphertext_len = 1 + key_len as usize + val_len as usize + 16;
+                let mut ciphertext = ByteString::with_capacity(ciphertext_len);
+                f.by_ref()
+                    .take(ciphertext_len as u64)
+                    .read_to_end(&mut ciphertext)?;
+                // Same short-read concern as the plaintext branch above: a
+                // torn trailing record must surface as an `UnexpectedEof`
+                // `RecordError`, not a panic.
+                if ciphertext.len() != ciphertext_len {
+                    return Err(RecordError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated record body",
+                    )));
+                }
+
+                let checksum = crc32::checksum_ieee(&ciphertext);
+                if checksum != saved_checksum {
+                    return Err(RecordError::ChecksumMismatch {
+                        offset,
+                        expected: saved_checksum,
+                        actual: checksum,
+                    });
+                }
+
+                encryption.decrypt(&nonce, &ciphertext)?
+            }
+        };
+
+        // The record type tag is the first byte of the checksummed (and,
+        // when encrypted, AEAD-sealed) payload, so corruption or tampering
+        // there is caught above rather than silently flipping a value into
+        // a tombstone or back.
+        let mut rest = data.split_off(1);
+        let record_type = RecordType::from_tag(offset, data[0])?;
+
+        let val = rest.split_off(key_len as usize);
+        let key = rest;
+
+        Ok(KeyValuePair {
+            key,
+            value: val,
+            is_tombstone: record_type == RecordType::Tombstone,
+        })
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        enum Lookup {
+            Position(u64),
+            Block(u64),
+            Missing,
+        }
+
+        let lookup = match &*self.index.lock().unwrap() {
+            Index::Full(map) => match map.get(key) {
+                Some(&position) => Lookup::Position(position),
+                None => Lookup::Missing,
+            },
+            Index::Sparse(entries) => match KvStore::candidate_block(entries, key) {
+                Some(offset) => Lookup::Block(offset),
+                None => Lookup::Missing,
+            },
+        };
+
+        match lookup {
+            Lookup::Missing => Ok(None),
+            Lookup::Position(position) => {
+                let kv = self.get_at(position)?;
+                Ok(if kv.is_tombstone { None } else { Some(kv.value) })
+            }
+            Lookup::Block(offset) => self.get_from_block(offset, key),
+        }
+    }
+
+    /// Looks up several keys at once. Every key is resolved against the
+    /// index up front, the resulting file offsets are sorted ascending, and
+    /// the reads are then issued in that order, turning what would
+    /// otherwise be scattered random access into a near-sequential sweep
+    /// over the file. Keys that aren't present are simply absent from the
+    /// returned map.
+    pub fn get_many(&mut self, keys: &[ByteString]) -> io::Result<HashMap<ByteString, ByteString>> {
+        enum Lookup<'a> {
+            Position(u64, &'a ByteString),
+            Block(u64, &'a ByteString),
+        }
 
+        let mut lookups = Vec::with_capacity(keys.len());
         {
-            // f.by_ref() is required because .take(n) creates a new Read instance. Using a reference within this block allows
-            // us to sidestep ownership issues, we then read data_len into the data buffer
-            f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+            let index = self.index.lock().unwrap();
+            for key in keys {
+                match &*index {
+                    Index::Full(map) => {
+                        if let Some(&position) = map.get(key.as_slice()) {
+                            lookups.push(Lookup::Position(position, key));
+                        }
+                    }
+                    Index::Sparse(entries) => {
+                        if let Some(offset) = KvStore::candidate_block(entries, key) {
+                            lookups.push(Lookup::Block(offset, key));
+                        }
+                    }
+                }
+            }
         }
-        debug_assert_eq!(data.len(), data_len as usize);
 
-        let checksum = crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!(
-                "data corruption encountered ({:08x} != {:08x})",
-                checksum, saved_checksum
-            );
+        lookups.sort_by_key(|lookup| match lookup {
+            Lookup::Position(offset, _) | Lookup::Block(offset, _) => *offset,
+        });
+
+        let mut results = HashMap::with_capacity(lookups.len());
+        for lookup in lookups {
+            match lookup {
+                Lookup::Position(position, key) => {
+                    let kv = self.get_at(position)?;
+                    if !kv.is_tombstone {
+                        results.insert(key.clone(), kv.value);
+                    }
+                }
+                Lookup::Block(offset, key) => {
+                    if let Some(value) = self.get_from_block(offset, key)? {
+                        results.insert(key.clone(), value);
+                    }
+                }
+            }
         }
 
-        let val = data.split_off(key_len as usize);
-        let key = data;
+        Ok(results)
+    }
 
-        Ok(KeyValuePair { key, value: val })
+    // The sparse index is sorted by each block's first key, so the only
+    // block that can contain `key` is the last one whose first key is
+    // still `<= key`.
+    fn candidate_block(entries: &[SparseIndexEntry], key: &ByteStr) -> Option<u64> {
+        let idx = entries.partition_point(|entry| entry.first_key.as_slice() <= key);
+        if idx == 0 {
+            None
+        } else {
+            Some(entries[idx - 1].offset)
+        }
     }
 
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        let position = match self.index.lock().unwrap().get(key) {
-            None => return Ok(None),
-            Some(pos) => *pos,
+    fn get_from_block(&mut self, block_offset: u64, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let block = {
+            let file_lock = self.f.lock().unwrap();
+            let mut f = BufReader::new(&*file_lock);
+            f.seek(SeekFrom::Start(block_offset))?;
+
+            match (self.compressed, self.encryption.as_ref()) {
+                (false, None) => {
+                    let block_len = f.read_u32::<LittleEndian>()?;
+                    let mut block = vec![0u8; block_len as usize];
+                    f.read_exact(&mut block)?;
+                    block
+                }
+                (true, None) => {
+                    let checksum = f.read_u32::<LittleEndian>()?;
+                    let uncompressed_len = f.read_u32::<LittleEndian>()?;
+                    let compressed_len = f.read_u32::<LittleEndian>()?;
+                    let mut compressed = vec![0u8; compressed_len as usize];
+                    f.read_exact(&mut compressed)?;
+
+                    let actual = crc32::checksum_ieee(&compressed);
+                    if actual != checksum {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "block checksum mismatch at offset {} ({:08x} != {:08x})",
+                                block_offset, checksum, actual
+                            ),
+                        ));
+                    }
+
+                    let block = snap::raw::Decoder::new().decompress_vec(&compressed).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("snappy decompression failed: {}", err),
+                        )
+                    })?;
+                    debug_assert_eq!(block.len(), uncompressed_len as usize);
+                    block
+                }
+                (false, Some(encryption)) => {
+                    let checksum = f.read_u32::<LittleEndian>()?;
+                    let body_len = f.read_u32::<LittleEndian>()?;
+                    let mut nonce = [0u8; NONCE_LEN];
+                    f.read_exact(&mut nonce)?;
+                    let mut ciphertext = vec![0u8; body_len as usize + 16];
+                    f.read_exact(&mut ciphertext)?;
+
+                    let actual = crc32::checksum_ieee(&ciphertext);
+                    if actual != checksum {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "block checksum mismatch at offset {} ({:08x} != {:08x})",
+                                block_offset, checksum, actual
+                            ),
+                        ));
+                    }
+
+                    let block = encryption.decrypt(&nonce, &ciphertext)?;
+                    debug_assert_eq!(block.len(), body_len as usize);
+                    block
+                }
+                (true, Some(encryption)) => {
+                    let checksum = f.read_u32::<LittleEndian>()?;
+                    let uncompressed_len = f.read_u32::<LittleEndian>()?;
+                    let compressed_len = f.read_u32::<LittleEndian>()?;
+                    let mut nonce = [0u8; NONCE_LEN];
+                    f.read_exact(&mut nonce)?;
+                    let mut ciphertext = vec![0u8; compressed_len as usize + 16];
+                    f.read_exact(&mut ciphertext)?;
+
+                    let actual = crc32::checksum_ieee(&ciphertext);
+                    if actual != checksum {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "block checksum mismatch at offset {} ({:08x} != {:08x})",
+                                block_offset, checksum, actual
+                            ),
+                        ));
+                    }
+
+                    let compressed = encryption.decrypt(&nonce, &ciphertext)?;
+                    let block = snap::raw::Decoder::new().decompress_vec(&compressed).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("snappy decompression failed: {}", err),
+                        )
+                    })?;
+                    debug_assert_eq!(block.len(), uncompressed_len as usize);
+                    block
+                }
+            }
         };
 
-        let kv = self.get_at(position)?;
+        for (candidate_key, value) in decode_block(&block)? {
+            if candidate_key == key {
+                return Ok(Some(value));
+            }
+            if candidate_key.as_slice() > key {
+                break;
+            }
+        }
 
-        Ok(Some(kv.value))
+        Ok(None)
     }
 
     pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
@@ -130,7 +1012,7 @@ impl KvStore {
         let mut f = BufReader::new(&*file_lock);
 
         f.seek(SeekFrom::Start(position))?;
-        let kv = KvStore::process_record(&mut f)?;
+        let kv = KvStore::process_record(&mut f, self.encryption.as_ref(), position)?;
 
         Ok(kv)
     }
@@ -138,13 +1020,54 @@ impl KvStore {
     pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
         let position = self.insert_but_ignore_index(key, value)?; // get position of the start of data
 
-        self.index.lock().unwrap().insert(key.to_vec(), position);
+        match &mut *self.index.lock().unwrap() {
+            Index::Full(map) => {
+                map.insert(key.to_vec(), position);
+            }
+            Index::Sparse(_) => unreachable!("insert_but_ignore_index rejects segment stores"),
+        }
         Ok(())
     }
 
+    /// Rewrites the log into a sorted, block-structured segment: live keys
+    /// are grouped into `BLOCK_TARGET_LEN`-ish blocks (each entry after a
+    /// block's first key stored as a shared-prefix-length/suffix delta), and
+    /// a trailing sparse index records only each block's first key and
+    /// offset. After this, `get` loads just that sparse index into memory
+    /// instead of one offset per key, keeping steady-state memory
+    /// proportional to block count rather than key count.
+    ///
+    /// That benefit only applies after compaction finishes: `compact` itself
+    /// still reads every live key and value into memory up front to sort
+    /// them, so running it needs memory proportional to total live data
+    /// size, same as before this segment format existed. Datasets too large
+    /// to sort in memory need an external/on-disk sort here instead, which
+    /// this implementation doesn't do.
     pub fn compact(&mut self) -> io::Result<()> {
-        let binding = self.index.clone();
-        let index_lock = binding.lock().unwrap();
+        let positions: Vec<u64> = match &*self.index.lock().unwrap() {
+            Index::Full(map) => map.values().copied().collect(),
+            Index::Sparse(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "store is already a compacted segment",
+                ))
+            }
+        };
+
+        self.write_segment(positions)
+    }
+
+    // Writes a fresh segment containing the records at `positions`,
+    // atomically swapping it in and replacing `self.index` with the
+    // resulting sparse index. Used by `compact` (every live key); `scan`'s
+    // fix mode rewrites through `write_plain_log` instead.
+    fn write_segment(&mut self, positions: Vec<u64>) -> io::Result<()> {
+        let mut live = Vec::with_capacity(positions.len());
+        for position in positions {
+            let kv = self.get_at(position)?;
+            live.push((kv.key, kv.value));
+        }
+        live.sort_by(|a, b| a.0.cmp(&b.0));
 
         let temp_path = "db2";
         let mut temp_file = OpenOptions::new()
@@ -154,25 +1077,95 @@ impl KvStore {
             .truncate(true) // truncate any existing data in the temp file
             .open(temp_path)?;
 
-        let mut new_index = HashMap::new();
+        temp_file.write_all(&build_header(self.encryption.as_ref(), true, self.compressed))?;
+
+        let mut sparse_index = Vec::new();
+        let mut block_entries: Vec<(ByteString, ByteString)> = Vec::new();
+        let mut block_len_estimate = 0usize;
+
+        for (key, value) in live {
+            if !block_entries.is_empty()
+                && block_len_estimate + key.len() + value.len() > BLOCK_TARGET_LEN
+            {
+                KvStore::flush_block(
+                    &mut temp_file,
+                    &mut block_entries,
+                    &mut sparse_index,
+                    self.compressed,
+                    self.encryption.as_ref(),
+                )?;
+                block_len_estimate = 0;
+            }
+
+            block_len_estimate += key.len() + value.len();
+            block_entries.push((key, value));
+        }
+
+        if !block_entries.is_empty() {
+            KvStore::flush_block(
+                &mut temp_file,
+                &mut block_entries,
+                &mut sparse_index,
+                self.compressed,
+                self.encryption.as_ref(),
+            )?;
+        }
+
+        let index_offset = temp_file.seek(SeekFrom::Current(0))?;
+        for entry in &sparse_index {
+            temp_file.write_u32::<LittleEndian>(entry.first_key.len() as u32)?;
+            temp_file.write_all(&entry.first_key)?;
+            temp_file.write_u64::<LittleEndian>(entry.offset)?;
+        }
+        temp_file.write_u64::<LittleEndian>(index_offset)?;
+
+        temp_file.flush()?;
+        temp_file.sync_all()?;
+
+        let db_path = "db";
+        fs::rename(temp_path, db_path)?;
 
-        for (_, &position) in &*index_lock {
+        self.f = Arc::new(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .append(true)
+                .open(db_path)?,
+        ));
+
+        // replace old index with the new sparse index
+        self.index = Arc::new(Mutex::new(Index::Sparse(sparse_index)));
+
+        Ok(())
+    }
+
+    // Rewrites the log so it contains only the records at `positions`, as a
+    // plain append-only log rather than a `compact`ed segment. Used by
+    // `scan`'s fix mode, which repairs a log without also converting it
+    // into a read-only-to-`insert`/`delete` segment the way `compact` does.
+    fn write_plain_log(&mut self, positions: Vec<u64>) -> io::Result<()> {
+        let mut live = Vec::with_capacity(positions.len());
+        for position in positions {
             let kv = self.get_at(position)?;
-            let new_position = temp_file.seek(SeekFrom::Current(0))?;
+            live.push((kv.key, kv.value));
+        }
 
-            let key_len = kv.key.len() as u32;
-            let val_len = kv.value.len() as u32;
-            let mut tmp = Vec::with_capacity(key_len as usize + val_len as usize);
-            tmp.extend_from_slice(&kv.key);
-            tmp.extend_from_slice(&kv.value);
-            let checksum = crc32::checksum_ieee(&tmp);
+        let temp_path = "db2";
+        let mut temp_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true) // truncate any existing data in the temp file
+            .open(temp_path)?;
 
-            temp_file.write_u32::<LittleEndian>(checksum)?;
-            temp_file.write_u32::<LittleEndian>(key_len)?;
-            temp_file.write_u32::<LittleEndian>(val_len)?;
-            temp_file.write_all(&tmp)?;
+        temp_file.write_all(&build_header(self.encryption.as_ref(), false, self.compressed))?;
 
-            new_index.insert(kv.key.clone(), new_position);
+        let mut index = HashMap::with_capacity(live.len());
+        for (key, value) in live {
+            let record = encode_record(&key, &value, RecordType::Value, self.encryption.as_ref())?;
+            let position = temp_file.seek(SeekFrom::Current(0))?;
+            temp_file.write_all(&record)?;
+            index.insert(key, position);
         }
 
         temp_file.flush()?;
@@ -188,41 +1181,151 @@ impl KvStore {
                 .append(true)
                 .open(db_path)?,
         ));
+        // `write_record` takes a new record's position from the handle's
+        // current offset, so leave the fresh handle at the end of the log.
+        self.f.lock().unwrap().seek(SeekFrom::End(0))?;
 
-        // replace old index with the new index
-        self.index = Arc::new(Mutex::new(new_index));
+        // replace old index with the rebuilt full index; the store stays a
+        // plain, writable log.
+        self.index = Arc::new(Mutex::new(Index::Full(index)));
 
         Ok(())
     }
 
-    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        let file_lock = self.f.lock().unwrap();
-        let mut f = BufWriter::new(&*file_lock);
+    // Encodes one block: entries as shared-prefix-length/suffix deltas off
+    // the previous entry, with a full (non-delta) key every
+    // `BLOCK_RESTART_INTERVAL` entries, followed by a footer of those
+    // restart offsets and their count.
+    fn flush_block(
+        temp_file: &mut File,
+        block_entries: &mut Vec<(ByteString, ByteString)>,
+        sparse_index: &mut Vec<SparseIndexEntry>,
+        compressed: bool,
+        encryption: Option<&Encryption>,
+    ) -> io::Result<()> {
+        let first_key = block_entries[0].0.clone();
+
+        let mut body = Vec::new();
+        let mut restarts = Vec::new();
+        let mut prev_key: ByteString = Vec::new();
 
-        let key_len = key.len();
-        let val_len = value.len();
+        for (i, (key, value)) in block_entries.iter().enumerate() {
+            let is_restart = i % BLOCK_RESTART_INTERVAL == 0;
+            let shared_len = if is_restart {
+                0
+            } else {
+                key.iter()
+                    .zip(prev_key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            };
+
+            if is_restart {
+                restarts.push(body.len() as u32);
+            }
 
-        // Buiild check sum
-        let mut tmp = ByteString::with_capacity(key_len + val_len);
+            let suffix = &key[shared_len..];
+            body.write_u32::<LittleEndian>(shared_len as u32)?;
+            body.write_u32::<LittleEndian>(suffix.len() as u32)?;
+            body.write_all(suffix)?;
+            body.write_u32::<LittleEndian>(value.len() as u32)?;
+            body.write_all(value)?;
 
-        for byte in key {
-            tmp.push(*byte);
+            prev_key = key.clone();
         }
 
-        for byte in value {
-            tmp.push(*byte)
+        for restart in &restarts {
+            body.write_u32::<LittleEndian>(*restart)?;
         }
+        body.write_u32::<LittleEndian>(restarts.len() as u32)?;
+
+        let block_offset = temp_file.seek(SeekFrom::Current(0))?;
+        let body_len = body.len() as u32;
+
+        // Snappy (if enabled) runs first, since encrypted bytes are
+        // high-entropy and don't compress; encryption (if enabled) then
+        // wraps whatever that produced, with the checksum computed over
+        // the ciphertext, mirroring `write_record`'s record format.
+        let stored = if compressed {
+            snap::raw::Encoder::new()
+                .compress_vec(&body)
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("snappy compression failed: {}", err),
+                    )
+                })?
+        } else {
+            body
+        };
 
-        let checksum = crc32::checksum_ieee(&tmp);
+        match encryption {
+            None if compressed => {
+                let checksum = crc32::checksum_ieee(&stored);
+
+                temp_file.write_u32::<LittleEndian>(checksum)?;
+                temp_file.write_u32::<LittleEndian>(body_len)?;
+                temp_file.write_u32::<LittleEndian>(stored.len() as u32)?;
+                temp_file.write_all(&stored)?;
+            }
+            None => {
+                temp_file.write_u32::<LittleEndian>(stored.len() as u32)?;
+                temp_file.write_all(&stored)?;
+            }
+            Some(encryption) => {
+                let (ciphertext, nonce) = encryption.encrypt(&stored)?;
+                let checksum = crc32::checksum_ieee(&ciphertext);
+
+                temp_file.write_u32::<LittleEndian>(checksum)?;
+                temp_file.write_u32::<LittleEndian>(body_len)?;
+                if compressed {
+                    temp_file.write_u32::<LittleEndian>(stored.len() as u32)?;
+                }
+                temp_file.write_all(&nonce)?;
+                temp_file.write_all(&ciphertext)?;
+            }
+        }
+
+        sparse_index.push(SparseIndexEntry {
+            first_key,
+            offset: block_offset,
+        });
+
+        block_entries.clear();
+        Ok(())
+    }
+
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
+        self.write_record(key, value, RecordType::Value)
+    }
+
+    // Shared by `insert_but_ignore_index` and `delete`: writes a record
+    // tagged as a live value or a tombstone. The tag is prepended to the
+    // key+value payload before the checksum (and, when encrypted, the
+    // AEAD) is computed, so corruption or tampering with it is caught the
+    // same way as corruption anywhere else in the record.
+    fn write_record(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+        record_type: RecordType,
+    ) -> io::Result<u64> {
+        if matches!(&*self.index.lock().unwrap(), Index::Sparse(_)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot write directly into a compacted segment; open a fresh log to keep writing",
+            ));
+        }
+
+        let record = encode_record(key, value, record_type, self.encryption.as_ref())?;
+
+        let file_lock = self.f.lock().unwrap();
+        let mut f = BufWriter::new(&*file_lock);
 
         let next_byte = SeekFrom::End(0);
         let current_position = f.seek(SeekFrom::Current(0))?;
         f.seek(next_byte)?;
-
-        f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u32::<LittleEndian>(val_len as u32)?;
-        f.write_all(&mut tmp)?;
+        f.write_all(&record)?;
 
         // We return the position where the data starts as thats what we actually need to
         // store in our index.
@@ -234,8 +1337,345 @@ impl KvStore {
         self.insert(key, value)
     }
 
-    #[inline]
+    /// Writes a tombstone for `key` so it reads back as `Ok(None)`, and
+    /// drops it from the index. Unlike the old empty-value delete, this
+    /// doesn't collide with a legitimately stored empty value, and
+    /// `compact` can tell the two apart and actually reclaim the space.
     pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
-        self.insert(key, b"")
+        self.write_record(key, b"", RecordType::Tombstone)?;
+
+        match &mut *self.index.lock().unwrap() {
+            Index::Full(map) => {
+                map.remove(key);
+            }
+            Index::Sparse(_) => unreachable!("write_record rejects segment stores"),
+        }
+        Ok(())
+    }
+}
+
+// Async read path for tokio-based services, gated behind the `async`
+// feature so the synchronous API above stays dependency-free by default.
+// `get`/`get_many` already do their own locking through `Arc<Mutex<_>>`, so
+// the blocking work just needs to run on tokio's blocking thread pool
+// rather than tying up an executor thread.
+#[cfg(feature = "async")]
+impl KvStore {
+    // The file and index handles are already shared through `Arc<Mutex<_>>`,
+    // so this clones handles rather than copying the log itself.
+    fn shallow_clone(&self) -> KvStore {
+        KvStore {
+            f: Arc::clone(&self.f),
+            index: Arc::clone(&self.index),
+            encryption: self.encryption.clone(),
+            compressed: self.compressed,
+            header_len: self.header_len,
+        }
+    }
+
+    /// Async variant of [`get`](KvStore::get).
+    pub async fn aget(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let mut store = self.shallow_clone();
+        let key = key.to_vec();
+        tokio::task::spawn_blocking(move || store.get(&key))
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    }
+
+    /// Async variant of [`get_many`](KvStore::get_many). Resolving the keys
+    /// against the index and sorting by offset happens up front, so the
+    /// blocking task performs a near-sequential sweep over the file instead
+    /// of one scattered read per key.
+    pub async fn aget_many(
+        &self,
+        keys: &[ByteString],
+    ) -> io::Result<HashMap<ByteString, ByteString>> {
+        let mut store = self.shallow_clone();
+        let keys = keys.to_vec();
+        tokio::task::spawn_blocking(move || store.get_many(&keys))
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    // `compact`/`write_plain_log` hard-code "db"/"db2" as the on-disk file
+    // names regardless of the path `open` was given, so every test that
+    // touches the filesystem gets its own temp directory as the current
+    // directory, and this lock keeps tests from racing over that shared
+    // process-wide state.
+    static DIR_LOCK: StdMutex<()> = StdMutex::new(());
+
+    fn in_temp_dir<T>(name: &str, f: impl FnOnce() -> T) -> T {
+        let _guard = DIR_LOCK.lock().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("kvs-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let original = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let result = f();
+        std::env::set_current_dir(original).unwrap();
+
+        result
+    }
+
+    #[test]
+    fn compact_then_reopen_round_trips_live_keys() {
+        in_temp_dir("compact-round-trip", || {
+            let mut store = KvStore::open(Path::new("db")).unwrap();
+            store.load().unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.delete(b"a").unwrap();
+            store.compact().unwrap();
+
+            let mut reopened = KvStore::open(Path::new("db")).unwrap();
+            reopened.load().unwrap();
+            assert_eq!(reopened.get(b"a").unwrap(), None);
+            assert_eq!(reopened.get(b"b").unwrap(), Some(b"2".to_vec()));
+        });
+    }
+
+    #[test]
+    fn encrypted_compact_round_trips_and_does_not_leak_plaintext() {
+        in_temp_dir("encrypted-compact-round-trip", || {
+            let passphrase = b"correct horse battery staple";
+            let mut store = KvStore::open_encrypted(
+                Path::new("db"),
+                passphrase,
+                EncryptionType::AesGcm,
+            )
+            .unwrap();
+            store.load().unwrap();
+            store.insert(b"secret-key", b"super-secret-value").unwrap();
+            store.compact().unwrap();
+
+            let on_disk = fs::read("db").unwrap();
+            assert!(
+                !on_disk
+                    .windows(b"super-secret-value".len())
+                    .any(|window| window == b"super-secret-value"),
+                "compacted segment leaked the plaintext value"
+            );
+            assert!(
+                !on_disk
+                    .windows(b"secret-key".len())
+                    .any(|window| window == b"secret-key"),
+                "compacted segment leaked the plaintext key"
+            );
+
+            let mut reopened =
+                KvStore::open_encrypted(Path::new("db"), passphrase, EncryptionType::AesGcm)
+                    .unwrap();
+            reopened.load().unwrap();
+            assert_eq!(
+                reopened.get(b"secret-key").unwrap(),
+                Some(b"super-secret-value".to_vec())
+            );
+        });
+    }
+
+    #[test]
+    fn scan_fix_mode_keeps_the_log_writable() {
+        in_temp_dir("scan-fix-writable", || {
+            let mut store = KvStore::open(Path::new("db")).unwrap();
+            store.load().unwrap();
+            store.insert(b"k1", b"v1").unwrap();
+            store.scan(true).unwrap();
+
+            // Before the fix, `scan`'s repair path rewrote through
+            // `write_segment`, which leaves `self.index` as `Index::Sparse`
+            // and makes `insert`/`delete` fail afterward.
+            store.insert(b"k2", b"v2").unwrap();
+            assert_eq!(store.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+            assert_eq!(store.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+        });
+    }
+
+    #[test]
+    fn scan_reports_valid_corrupt_and_truncated_counts() {
+        in_temp_dir("scan-stats", || {
+            let mut store = KvStore::open(Path::new("db")).unwrap();
+            store.load().unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.insert(b"c", b"3").unwrap();
+
+            // Every record here is the same size: a 4-byte checksum, two
+            // 4-byte lengths, then a 1-byte tag plus a 1-byte key and value.
+            let record_len = 12 + 1 + 1 + 1;
+            let header_len = store.header_len;
+
+            {
+                // The store's own handle is in append mode, which would put
+                // every write at the end; a separate handle can overwrite.
+                let mut file = OpenOptions::new().read(true).write(true).open("db").unwrap();
+
+                // Flip a byte inside "b"'s body so its checksum fails, while
+                // its length header (and so the offset of "c") stays intact.
+                let corrupt_offset = header_len + record_len as u64 + 12;
+                file.seek(SeekFrom::Start(corrupt_offset)).unwrap();
+                let mut byte = [0u8; 1];
+                file.read_exact(&mut byte).unwrap();
+                file.seek(SeekFrom::Start(corrupt_offset)).unwrap();
+                file.write_all(&[byte[0] ^ 0xff]).unwrap();
+
+                // Chop "c"'s body off entirely, leaving only its (intact)
+                // length header, to simulate a crash mid-write.
+                let file_len = file.metadata().unwrap().len();
+                file.set_len(file_len - 3).unwrap();
+            }
+
+            let stats = store.scan(false).unwrap();
+            assert_eq!(stats.valid, 1);
+            assert_eq!(stats.corrupt, 1);
+            assert_eq!(stats.truncated, 1);
+        });
+    }
+
+    #[test]
+    fn open_rejects_bad_magic_and_wrong_version() {
+        in_temp_dir("header-validation", || {
+            fs::write("db", b"not a kvs file at all").unwrap();
+            let err = KvStore::open(Path::new("db")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("magic"));
+
+            let mut store = KvStore::open(Path::new("other")).unwrap();
+            store.load().unwrap();
+            drop(store);
+
+            let mut bytes = fs::read("other").unwrap();
+            let version_offset = MAGIC.len();
+            bytes[version_offset] = FORMAT_VERSION + 1;
+            fs::write("other", &bytes).unwrap();
+
+            let err = KvStore::open(Path::new("other")).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("version"));
+        });
+    }
+
+    #[test]
+    fn block_round_trips_through_flush_and_candidate_lookup() {
+        in_temp_dir("block-round-trip", || {
+            let mut store = KvStore::open(Path::new("db")).unwrap();
+            store.load().unwrap();
+
+            let mut block_entries = vec![
+                (b"apple".to_vec(), b"fruit-1".to_vec()),
+                (b"apricot".to_vec(), b"fruit-2".to_vec()),
+                (b"banana".to_vec(), b"fruit-3".to_vec()),
+            ];
+            let mut sparse_index = Vec::new();
+            {
+                let file_lock = store.f.lock().unwrap();
+                let mut file = file_lock.try_clone().unwrap();
+                file.seek(SeekFrom::End(0)).unwrap();
+                KvStore::flush_block(
+                    &mut file,
+                    &mut block_entries,
+                    &mut sparse_index,
+                    false,
+                    None,
+                )
+                .unwrap();
+            }
+            store.index = Arc::new(Mutex::new(Index::Sparse(sparse_index)));
+
+            assert_eq!(store.get(b"apricot").unwrap(), Some(b"fruit-2".to_vec()));
+            assert_eq!(store.get(b"banana").unwrap(), Some(b"fruit-3".to_vec()));
+            assert_eq!(store.get(b"missing").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn compressed_block_round_trips_through_flush_and_candidate_lookup() {
+        in_temp_dir("compressed-block-round-trip", || {
+            let mut store = KvStore::open_compressed(Path::new("db")).unwrap();
+            store.load().unwrap();
+
+            let mut block_entries = vec![
+                (b"apple".to_vec(), b"fruit-1".to_vec()),
+                (b"apricot".to_vec(), b"fruit-2".to_vec()),
+                (b"banana".to_vec(), b"fruit-3".to_vec()),
+            ];
+            let mut sparse_index = Vec::new();
+            {
+                let file_lock = store.f.lock().unwrap();
+                let mut file = file_lock.try_clone().unwrap();
+                file.seek(SeekFrom::End(0)).unwrap();
+                KvStore::flush_block(
+                    &mut file,
+                    &mut block_entries,
+                    &mut sparse_index,
+                    true,
+                    None,
+                )
+                .unwrap();
+            }
+            store.index = Arc::new(Mutex::new(Index::Sparse(sparse_index)));
+
+            assert_eq!(store.get(b"apricot").unwrap(), Some(b"fruit-2".to_vec()));
+            assert_eq!(store.get(b"missing").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn get_many_resolves_present_keys_and_skips_missing_and_deleted_ones() {
+        in_temp_dir("get-many", || {
+            let mut store = KvStore::open(Path::new("db")).unwrap();
+            store.load().unwrap();
+            store.insert(b"a", b"1").unwrap();
+            store.insert(b"b", b"2").unwrap();
+            store.insert(b"c", b"3").unwrap();
+            store.delete(b"b").unwrap();
+
+            let keys = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"missing".to_vec()];
+            let results = store.get_many(&keys).unwrap();
+
+            assert_eq!(results.len(), 2);
+            assert_eq!(results.get(b"a".as_slice()), Some(&b"1".to_vec()));
+            assert_eq!(results.get(b"c".as_slice()), Some(&b"3".to_vec()));
+            assert_eq!(results.get(b"b".as_slice()), None);
+            assert_eq!(results.get(b"missing".as_slice()), None);
+        });
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn aget_and_aget_many_match_their_sync_counterparts() {
+        // `in_temp_dir` only wraps synchronous work; `spawn_blocking` runs
+        // on a separate thread but still sees the process-wide current
+        // directory, so the directory swap has to stay in place for the
+        // whole async body rather than just the setup step.
+        let _guard = DIR_LOCK.lock().unwrap();
+        let dir = std::env::temp_dir().join(format!("kvs-test-aget-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let original = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+
+        let mut store = KvStore::open(Path::new("db")).unwrap();
+        store.load().unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+
+        assert_eq!(store.aget(b"a").await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.aget(b"missing").await.unwrap(), None);
+
+        let keys = vec![b"a".to_vec(), b"b".to_vec(), b"missing".to_vec()];
+        let results = store.aget_many(&keys).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.get(b"a".as_slice()), Some(&b"1".to_vec()));
+        assert_eq!(results.get(b"b".as_slice()), Some(&b"2".to_vec()));
+
+        std::env::set_current_dir(original).unwrap();
     }
 }